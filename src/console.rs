@@ -0,0 +1,94 @@
+//! Line-oriented command console served over the CDC-ACM serial port.
+//!
+//! Lets a terminal attached to the USB serial port inspect and tweak the
+//! keyboard at runtime, without reflashing: dump the active/default layer,
+//! show recently debounced matrix events, check which half is the USB host,
+//! adjust the HoldTap timeout, and trigger a DFU bootloader jump.
+
+use keyberon::layout::Event;
+
+/// Number of debounced matrix events retained for the `log` command.
+const EVENT_LOG_LEN: usize = 8;
+
+/// Longest command line accepted before it is discarded unparsed.
+const LINE_LEN: usize = 32;
+
+/// Ring buffer of the most recently debounced matrix events.
+#[derive(Default)]
+pub struct EventLog {
+    events: [Option<Event>; EVENT_LOG_LEN],
+    next: usize,
+}
+
+impl EventLog {
+    /// Records a debounced matrix event.
+    pub fn push(&mut self, event: Event) {
+        self.events[self.next % EVENT_LOG_LEN] = Some(event);
+        self.next = self.next.wrapping_add(1);
+    }
+
+    /// Iterates the log oldest-to-newest.
+    pub fn iter(&self) -> impl Iterator<Item = Event> + '_ {
+        (0..EVENT_LOG_LEN)
+            .map(move |i| self.events[(self.next + i) % EVENT_LOG_LEN])
+            .flatten()
+    }
+}
+
+/// A command parsed from a line of console input.
+pub enum Command {
+    /// `layers` - dump the active and default layer.
+    Layers,
+    /// `log` - dump the recent debounced matrix events.
+    Log,
+    /// `host` - report whether this half is the USB host.
+    Host,
+    /// `timeout <ms>` - set the HoldTap timeout, in milliseconds.
+    Timeout(u16),
+    /// `bootloader` - jump to the DFU bootloader.
+    Bootloader,
+}
+
+/// Accumulates bytes received over the serial port into lines and parses
+/// them into [`Command`]s.
+#[derive(Default)]
+pub struct Console {
+    line: heapless::String<LINE_LEN>,
+    overlong: bool,
+}
+
+impl Console {
+    /// Feeds one received byte, returning a command once a full line
+    /// (terminated by `\n` or `\r`) has been parsed. Unrecognized lines are
+    /// silently dropped.
+    pub fn feed(&mut self, byte: u8) -> Option<Command> {
+        if byte == b'\n' || byte == b'\r' {
+            let cmd = if self.overlong {
+                None
+            } else {
+                parse(self.line.trim())
+            };
+            self.line.clear();
+            self.overlong = false;
+            cmd
+        } else {
+            // drop overlong lines instead of panicking on a full buffer
+            if self.line.push(byte as char).is_err() {
+                self.overlong = true;
+            }
+            None
+        }
+    }
+}
+
+fn parse(line: &str) -> Option<Command> {
+    let mut parts = line.split_whitespace();
+    match parts.next()? {
+        "layers" => Some(Command::Layers),
+        "log" => Some(Command::Log),
+        "host" => Some(Command::Host),
+        "timeout" => parts.next()?.parse().ok().map(Command::Timeout),
+        "bootloader" => Some(Command::Bootloader),
+        _ => None,
+    }
+}
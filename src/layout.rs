@@ -1,44 +1,89 @@
 use keyberon::action::{k, m, Action::*, HoldTapAction, HoldTapConfig};
 use keyberon::key_code::KeyCode::*;
 
-type Action = keyberon::action::Action<()>;
+/// Device-level actions dispatched from the layout's custom channel, beyond
+/// what a plain keycode can express.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CustomAction {
+    /// Jump to the DFU bootloader.
+    Bootloader,
+    /// Switch the underglow strip to the given [`crate::leds::LedMode`] (by index).
+    LedMode(u8),
+    /// Step the underglow strip's brightness up.
+    LedBrightnessUp,
+    /// Step the underglow strip's brightness down.
+    LedBrightnessDown,
+}
+
+type Action = keyberon::action::Action<CustomAction>;
 
 static DLAYER: Action = Action::DefaultLayer(0);
 static QWERTZLAYER: Action = Action::DefaultLayer(4);
 
 const TIMEOUT: u16 = 200;
 
-const SHIFT_SP: Action = HoldTap(&HoldTapAction {
+// The HoldTap actions below live in `static mut` storage (rather than being
+// `const`) so `set_holdtap_timeout` can tweak their `timeout` field from the
+// console at runtime, without a reflash.
+
+static mut SHIFT_SP_ACTION: HoldTapAction<CustomAction> = HoldTapAction {
     timeout: TIMEOUT,
     tap_hold_interval: 200,
     config: HoldTapConfig::Default,
     hold: k(LShift),
     tap: k(Space),
-});
+};
 
-const CTRL_TAB: Action = HoldTap(&HoldTapAction {
+static mut CTRL_TAB_ACTION: HoldTapAction<CustomAction> = HoldTapAction {
     timeout: TIMEOUT,
     tap_hold_interval: 200,
     config: HoldTapConfig::Default,
     hold: k(LCtrl),
     tap: k(Tab),
-});
+};
 
-const ALT_ENT: Action = HoldTap(&HoldTapAction {
+static mut ALT_ENT_ACTION: HoldTapAction<CustomAction> = HoldTapAction {
     timeout: TIMEOUT,
     tap_hold_interval: 200,
     config: HoldTapConfig::Default,
     hold: k(LAlt),
     tap: k(Enter),
-});
+};
 
-const PPN: Action = HoldTap(&HoldTapAction {
+static mut PPN_ACTION: HoldTapAction<CustomAction> = HoldTapAction {
     timeout: TIMEOUT,
     tap_hold_interval: 200,
     config: HoldTapConfig::Default,
     hold: k(MediaNextSong),
     tap: k(MediaPlayPause),
-});
+};
+
+#[allow(static_mut_refs)]
+static SHIFT_SP: Action = HoldTap(unsafe { &SHIFT_SP_ACTION });
+#[allow(static_mut_refs)]
+static CTRL_TAB: Action = HoldTap(unsafe { &CTRL_TAB_ACTION });
+#[allow(static_mut_refs)]
+static ALT_ENT: Action = HoldTap(unsafe { &ALT_ENT_ACTION });
+#[allow(static_mut_refs)]
+static PPN: Action = HoldTap(unsafe { &PPN_ACTION });
+
+/// Updates the HoldTap timeout (in milliseconds) used by all HoldTap keys
+/// (shift/space, ctrl/tab, alt/enter, the media next/play-pause key), taking
+/// effect on the next tick.
+///
+/// Must only be called from the same priority as the `layout.tick()` call
+/// that evaluates these HoldTap actions (the `tick` RTIC task, priority 1):
+/// the backing statics are plain `static mut`s with no locking of their own,
+/// so calling this from another priority would race that read.
+#[allow(static_mut_refs)]
+pub fn set_holdtap_timeout(timeout_ms: u16) {
+    unsafe {
+        SHIFT_SP_ACTION.timeout = timeout_ms;
+        CTRL_TAB_ACTION.timeout = timeout_ms;
+        ALT_ENT_ACTION.timeout = timeout_ms;
+        PPN_ACTION.timeout = timeout_ms;
+    }
+}
 
 macro_rules! s {
     ($k:ident) => {
@@ -51,8 +96,15 @@ macro_rules! a {
     };
 }
 
+static LED_SOLID: Action = Custom(CustomAction::LedMode(0));
+static LED_BREATHING: Action = Custom(CustomAction::LedMode(1));
+static LED_OFF: Action = Custom(CustomAction::LedMode(2));
+static LED_BRIGHTER: Action = Custom(CustomAction::LedBrightnessUp);
+static LED_DIMMER: Action = Custom(CustomAction::LedBrightnessDown);
+static BOOTLOADER: Action = Custom(CustomAction::Bootloader);
+
 #[rustfmt::skip]
-pub static LAYERS: keyberon::layout::Layers<12, 4, 5, ()> = keyberon::layout::layout! {
+pub static LAYERS: keyberon::layout::Layers<12, 4, 5, CustomAction> = keyberon::layout::layout! {
     {
         [ J     Y     Z     U     A     Q     P     B     M     L     F       -  ],
         [(1)    C     S     I     E     O     D     T     N     R     H      (1) ],
@@ -69,10 +121,10 @@ pub static LAYERS: keyberon::layout::Layers<12, 4, 5, ()> = keyberon::layout::la
         [ t  Escape Tab    n    Enter  n      Kb0    Kb1 Kb2 Kb3 Comma    {s!(Kb7)}],
         [ t  t      t      t    t      t      t      t   t   t   t        t],
     }{
-        [{Custom(())}  n    n     n     VolUp    n   F12  F7  F8  F9  n  {Custom(())}],
-        [t             n    n     n     {PPN}    n   F11  F4  F5  F6  n  t],
-        [n             n    n     n     VolDown  n   F10  F1  F2  F3  n  n],
-        [t             t    t     t     t        t   t    t   {QWERTZLAYER} t   t   t],
+        [{BOOTLOADER}  {LED_SOLID} {LED_BREATHING} {LED_OFF}     VolUp    n   F12  F7  F8  F9  n  {BOOTLOADER}],
+        [t             n           n               n             {PPN}    n   F11  F4  F5  F6  n  t],
+        [{LED_BRIGHTER} n          n               n             VolDown  n   F10  F1  F2  F3  n  {LED_DIMMER}],
+        [t             t           t               t             t        t   t    t   {QWERTZLAYER} t   t   t],
     }{
          [ Tab    Q   W   E   R   T     Y   U   I   O   P   BSpace ]
          [ LCtrl  A   S   D   F   G     H   J   K   L   ;   Quote  ]
@@ -0,0 +1,112 @@
+//! WS2812 ("NeoPixel") underglow strip driven by the layout's active layer.
+
+use smart_leds::{brightness, gamma, SmartLedsWrite, RGB8};
+use ws2812_spi::Ws2812;
+
+/// Number of LEDs in the underglow strip.
+pub const NUM_LEDS: usize = 6;
+
+/// Peak brightness passed to the gamma-corrected `brightness()` wrapper.
+const MAX_BRIGHTNESS: u8 = 32;
+const MIN_BRIGHTNESS: u8 = 4;
+const BRIGHTNESS_STEP: u8 = 4;
+
+/// Number of `set_layer` calls (ticks) per half-cycle of the breathing animation.
+const BREATHE_PERIOD: u16 = 512;
+
+/// Animation mode for the underglow strip, selected via `Custom(LedMode(_))`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum LedMode {
+    Solid,
+    Breathing,
+    Off,
+}
+
+impl From<u8> for LedMode {
+    fn from(index: u8) -> Self {
+        match index {
+            1 => LedMode::Breathing,
+            2 => LedMode::Off,
+            _ => LedMode::Solid,
+        }
+    }
+}
+
+/// Drives an underglow strip, mapping the layout's active layer to a color.
+pub struct Leds<SPI> {
+    driver: Ws2812<SPI>,
+    mode: LedMode,
+    brightness: u8,
+    phase: u16,
+}
+
+impl<SPI, E> Leds<SPI>
+where
+    SPI: embedded_hal::blocking::spi::Write<u8, Error = E>,
+{
+    /// Wraps an SPI peripheral (configured around 3 MHz, MOSI only) as an
+    /// underglow driver.
+    pub fn new(spi: SPI) -> Self {
+        Self {
+            driver: Ws2812::new(spi),
+            mode: LedMode::Solid,
+            brightness: MAX_BRIGHTNESS,
+            phase: 0,
+        }
+    }
+
+    /// Lights the whole strip in the color associated with `layer`, animated
+    /// according to the current mode.
+    pub fn set_layer(&mut self, layer: usize) {
+        let level = match self.mode {
+            LedMode::Off => 0,
+            LedMode::Solid => self.brightness,
+            LedMode::Breathing => {
+                self.phase = (self.phase + 1) % (2 * BREATHE_PERIOD);
+                let half = if self.phase < BREATHE_PERIOD {
+                    self.phase
+                } else {
+                    2 * BREATHE_PERIOD - self.phase
+                };
+                MIN_BRIGHTNESS
+                    + (half * u16::from(self.brightness - MIN_BRIGHTNESS) / BREATHE_PERIOD) as u8
+            }
+        };
+
+        let pixels = [layer_color(layer); NUM_LEDS];
+        self.driver
+            .write(brightness(gamma(pixels.into_iter()), level))
+            .ok();
+    }
+
+    /// Switches the animation mode (solid / breathing / off).
+    pub fn set_mode(&mut self, mode: LedMode) {
+        self.mode = mode;
+        self.phase = 0;
+    }
+
+    /// Steps the peak brightness up.
+    pub fn brightness_up(&mut self) {
+        self.brightness = (self.brightness + BRIGHTNESS_STEP).min(MAX_BRIGHTNESS);
+    }
+
+    /// Steps the peak brightness down.
+    pub fn brightness_down(&mut self) {
+        self.brightness = self
+            .brightness
+            .saturating_sub(BRIGHTNESS_STEP)
+            .max(MIN_BRIGHTNESS);
+    }
+}
+
+/// Maps a layout layer index to its underglow color.
+fn layer_color(layer: usize) -> RGB8 {
+    match layer {
+        0 => RGB8 { r: 0, g: 16, b: 0 },  // Colemak default layer
+        1 => RGB8 { r: 0, g: 0, b: 16 },  // symbol momentary layer
+        2 => RGB8 { r: 16, g: 0, b: 16 }, // nav momentary layer
+        3 => RGB8 { r: 16, g: 8, b: 0 },  // tri-state layer
+        4 => RGB8 { r: 16, g: 0, b: 0 },  // QWERTZ default layer
+        _ => RGB8::default(),
+    }
+}
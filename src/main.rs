@@ -20,7 +20,14 @@ use usb_device::prelude::*;
 
 use panic_probe as _;
 
+pub mod console;
 pub mod layout;
+pub mod leds;
+pub mod proto;
+
+use console::{Command, Console, EventLog};
+use layout::CustomAction;
+use leds::Leds;
 
 /// USB VIP for a generic keyboard from
 /// https://github.com/obdev/v-usb/blob/master/usbdrv/USB-IDs-for-free.txt
@@ -52,8 +59,30 @@ pub fn exit() -> ! {
     }
 }
 
+/// How often (in 1 kHz `tick`s) the host re-sends its identity handshake to
+/// the other half, in case an earlier `Hello` was lost to a CRC mismatch.
+const HELLO_INTERVAL_TICKS: u16 = 1000;
+
 type UsbClass = keyberon::Class<'static, UsbBusType, ()>;
 type UsbDevice = usb_device::device::UsbDevice<'static, UsbBusType>;
+type SerialClass = usbd_serial::SerialPort<'static, UsbBusType>;
+
+/// Snapshot of the layout state the console reports with the `layers` command.
+#[derive(Default, Clone, Copy)]
+struct LayerSnapshot {
+    current: usize,
+    default: usize,
+}
+
+/// SPI peripheral driving the underglow strip (MOSI only, no SCK/MISO wired).
+type LedsSpi = hal::spi::Spi<
+    hal::pac::SPI2,
+    (
+        hal::spi::NoSck,
+        hal::spi::NoMiso,
+        hal::gpio::gpioc::PC3<hal::gpio::Alternate<5>>,
+    ),
+>;
 
 #[rtic::app(device = stm32f4xx_hal::pac, dispatchers=[TIM1_CC])]
 mod app {
@@ -63,8 +92,31 @@ mod app {
     struct Shared {
         usb_dev: UsbDevice,
         usb_class: UsbClass,
+        serial_class: SerialClass,
+        layer_snapshot: LayerSnapshot,
+        event_log: EventLog,
+        /// Set once this half receives a [`proto::Frame::Hello`], i.e. once
+        /// the other half has announced itself as the USB host. Read by
+        /// `tick` (to decide whether to mirror locally scanned events before
+        /// forwarding them, and whether it is safe to forward them at all)
+        /// and written by `rx` (on handshake receipt) - never persisted, so
+        /// the role is renegotiated on every boot.
+        is_remote: bool,
+        /// A HoldTap timeout (in ms) requested via the console's `timeout`
+        /// command, handed off from `usb_tx` to `tick` through a lock rather
+        /// than written to `layout`'s backing statics directly - `tick` (and
+        /// the layout it drives) only ever runs at priority 1, so applying
+        /// it there keeps every access to those statics at a single
+        /// priority instead of racing a priority-3 write against it.
+        pending_holdtap_timeout: Option<u16>,
+        /// The host's active layout layer, as last reported over USART.
+        /// Written by `rx` (on receipt of a [`proto::Frame::Layer`]) and read
+        /// by `tick` to drive the underglow strip on the remote half, whose
+        /// own `layout` never leaves the default layer (it only forwards
+        /// events, it never processes them).
+        remote_layer: usize,
         #[lock_free]
-        layout: Layout<12, 4, 5, ()>,
+        layout: Layout<12, 4, 5, CustomAction>,
     }
 
     // local resources (between tasks)
@@ -75,7 +127,19 @@ mod app {
         timer: hal::timer::counter::CounterHz<hal::pac::TIM2>,
         serial_tx: serial::Tx<hal::pac::USART1>,
         serial_rx: serial::Rx<hal::pac::USART1>,
-        serial_buf: [u8; 4],
+        serial_buf: [u8; proto::MAX_FRAME_LEN],
+        serial_pos: usize,
+        /// Ticks until the host-identity handshake is (re)sent to the other
+        /// half. Counts down to 0 while this half is the host, re-arming
+        /// itself to [`HELLO_INTERVAL_TICKS`] after every send, so a single
+        /// dropped (CRC-mismatched) `Hello` doesn't strand the other half
+        /// unmirrored for the rest of the power cycle.
+        hello_countdown: u16,
+        /// The layer last reported to the other half, so a `Frame::Layer` is
+        /// only sent on an actual change.
+        last_sent_layer: Option<usize>,
+        leds: Leds<LedsSpi>,
+        console: Console,
     }
 
     #[init]
@@ -99,6 +163,7 @@ mod app {
         // get GPIO pins
         let gpioa = cx.device.GPIOA.split();
         let gpiob = cx.device.GPIOB.split();
+        let gpioc = cx.device.GPIOC.split();
 
         // timer for processing keyboard events and sending a USB keyboard report
         let mut timer = cx.device.TIM2.counter_hz(&mut clocks);
@@ -123,10 +188,14 @@ mod app {
 
         let usb_bus = unsafe { USB_BUS.as_ref().unwrap() };
         let usb_class = keyberon::new_class(&usb_bus, ());
+        let serial_class = usbd_serial::SerialPort::new(usb_bus);
+        // composite device: keyboard HID + CDC-ACM console, via an IAD so
+        // both classes show up properly on hosts that need one
         let usb_dev = UsbDeviceBuilder::new(usb_bus, UsbVidPid(VID, PID))
             .manufacturer("Dario Götz")
             .product("Dario Götz's 42-key split keyboard")
             .serial_number(env!("CARGO_PKG_VERSION"))
+            .composite_with_iads()
             .build();
 
         // Setup USART communication with other half
@@ -186,6 +255,17 @@ mod app {
         ];
         let matrix = cortex_m::interrupt::free(move |_cs| DirectPinMatrix::new(matrix_pins));
 
+        // Underglow strip: MOSI-only SPI2, no SCK/MISO wired, clocked so each
+        // WS2812 bit is encoded as three SPI bits (0b110 / 0b100).
+        let leds_mosi = gpioc.pc3.into_alternate();
+        let leds_spi = cx.device.SPI2.spi(
+            (hal::spi::NoSck, hal::spi::NoMiso, leds_mosi),
+            ws2812_spi::MODE,
+            3.MHz(),
+            &clocks,
+        );
+        let leds = Leds::new(leds_spi);
+
         let mut layout = Layout::new(&layout::LAYERS);
         layout.add_tri_state_layer((1, 2), 3);
 
@@ -194,6 +274,12 @@ mod app {
                 // Initialization of shared resources go here
                 usb_dev,
                 usb_class,
+                serial_class,
+                layer_snapshot: LayerSnapshot::default(),
+                event_log: EventLog::default(),
+                is_remote: false,
+                pending_holdtap_timeout: None,
+                remote_layer: 0,
                 layout,
             },
             Local {
@@ -203,7 +289,12 @@ mod app {
                 debouncer: Debouncer::new([[false; 6]; 4], [[false; 6]; 4], 5),
                 serial_tx,
                 serial_rx,
-                serial_buf: [0; 4],
+                serial_buf: [0; proto::MAX_FRAME_LEN],
+                serial_pos: 0,
+                hello_countdown: 0,
+                last_sent_layer: None,
+                leds,
+                console: Console::default(),
             },
             init::Monotonics(),
         )
@@ -229,27 +320,61 @@ mod app {
 
     /// Check all switches for their state, register corresponding events, and
     /// spawn generation of a USB keyboard report (including layout event processing)
-    #[task(binds=TIM2, priority=1, local=[debouncer, matrix, timer, serial_tx], shared=[usb_dev, usb_class, layout])]
+    #[task(binds=TIM2, priority=1, local=[debouncer, matrix, timer, serial_tx, hello_countdown, last_sent_layer, leds], shared=[usb_dev, usb_class, layout, event_log, layer_snapshot, is_remote, pending_holdtap_timeout, remote_layer])]
     fn tick(mut cx: tick::Context) {
         // defmt::info!("Processing keyboard events");
         let is_host = cx.shared.usb_dev.lock(|d| d.state()) == UsbDeviceState::Configured;
 
+        // Apply any HoldTap timeout requested via the console. Done here
+        // (rather than in `usb_tx`, where the request is made) so the write
+        // to `layout`'s backing statics stays at the same priority as the
+        // `layout.tick()` call below that reads them.
+        if let Some(ms) = cx.shared.pending_holdtap_timeout.lock(|t| t.take()) {
+            layout::set_holdtap_timeout(ms);
+        }
+
         cx.local.timer.wait().ok();
         // or equivalently
         // cx.local.timer.clear_interrupt(hal::timer::Event::Update);
 
+        // Announce ourselves to the other half once we enumerate, so it
+        // knows to mirror its own coordinates before forwarding them. Kept
+        // up on a periodic resend (rather than fired once) so a `Hello`
+        // dropped to a CRC mismatch doesn't strand the other half
+        // unmirrored for the rest of the power cycle, and re-armed whenever
+        // we are not the host so a replug renegotiates from scratch.
+        if is_host {
+            if *cx.local.hello_countdown == 0 {
+                let mut frame = [0; proto::MAX_FRAME_LEN];
+                let len = proto::serialize(proto::Frame::Hello, &mut frame);
+                for &b in &frame[..len] {
+                    block!(cx.local.serial_tx.write(b)).unwrap();
+                }
+                *cx.local.hello_countdown = HELLO_INTERVAL_TICKS;
+            } else {
+                *cx.local.hello_countdown -= 1;
+            }
+        } else {
+            *cx.local.hello_countdown = 0;
+        }
+        let is_remote = cx.shared.is_remote.lock(|r| *r);
+
         // scan keyboard
-        for event in cx
-            .local
-            .debouncer
-            .events(cx.local.matrix.get().unwrap())
-            .map(transform_keypress_coordinates)
-        {
-            // either register events or send to other half
+        for event in cx.local.debouncer.events(cx.local.matrix.get().unwrap()) {
+            cx.shared.event_log.lock(|log| log.push(event));
+
+            // Register events locally, or mirror and forward them to the
+            // host. Until we've positively resolved our role (seen either
+            // our own USB enumerate, or a `Hello` from the other half), drop
+            // the event instead of forwarding it unmirrored and risking a
+            // silently wrong keycode on the host.
             if is_host {
                 cx.shared.layout.event(event)
-            } else {
-                for &b in &serialize(event) {
+            } else if is_remote {
+                let event = event.transform(|i, j| (i, 11 - j));
+                let mut frame = [0; proto::MAX_FRAME_LEN];
+                let len = proto::serialize(proto::Frame::Event(event), &mut frame);
+                for &b in &frame[..len] {
                     block!(cx.local.serial_tx.write(b)).unwrap();
                 }
             }
@@ -261,10 +386,42 @@ mod app {
 
         let tick = cx.shared.layout.tick();
         match tick {
-            CustomEvent::Release(()) => unsafe { cortex_m::asm::bootload(0x1FFF0000 as _) },
+            CustomEvent::Release(CustomAction::Bootloader) => unsafe {
+                cortex_m::asm::bootload(0x1FFF0000 as _)
+            },
+            CustomEvent::Press(CustomAction::LedMode(mode)) => cx.local.leds.set_mode(mode.into()),
+            CustomEvent::Press(CustomAction::LedBrightnessUp) => cx.local.leds.brightness_up(),
+            CustomEvent::Press(CustomAction::LedBrightnessDown) => cx.local.leds.brightness_down(),
             _ => (),
         }
 
+        // Reflect the active layer in the underglow strip. The remote half
+        // never processes events with its own `layout` (it only forwards
+        // them), so it tracks the host's layer via `remote_layer` instead,
+        // kept up to date by `rx` from the host's `Frame::Layer` reports.
+        if is_host {
+            let current = cx.shared.layout.current_layer();
+            if *cx.local.last_sent_layer != Some(current) {
+                let mut frame = [0; proto::MAX_FRAME_LEN];
+                let len = proto::serialize(proto::Frame::Layer(current as u8), &mut frame);
+                for &b in &frame[..len] {
+                    block!(cx.local.serial_tx.write(b)).unwrap();
+                }
+                *cx.local.last_sent_layer = Some(current);
+            }
+            cx.local.leds.set_layer(current);
+        } else {
+            cx.local.leds.set_layer(cx.shared.remote_layer.lock(|l| *l));
+        }
+
+        // keep a snapshot of the layer state for the serial console
+        cx.shared.layer_snapshot.lock(|snap| {
+            *snap = LayerSnapshot {
+                current: cx.shared.layout.current_layer(),
+                default: cx.shared.layout.default_layer(),
+            };
+        });
+
         // if this is the USB-side, send a USB keyboard report
         if is_host {
             let report: KbHidReport = cx.shared.layout.keycodes().collect();
@@ -278,70 +435,153 @@ mod app {
         }
     }
 
-    /// Receive USART events from other keyboard half and register them
-    #[task(binds = USART1, priority = 2, local = [serial_rx, serial_buf])]
-    fn rx(cx: rx::Context) {
-        // receive USART bytes and place into local buffer
-        // if buffer is full (ends with '\n'), spawn event registration
-        // received events (from other half) are mirrored (transformed)
+    /// Receive USART bytes from the other keyboard half, accumulating them
+    /// into a COBS frame until the `0x00` delimiter is seen, then decode and
+    /// CRC-check it. A [`proto::Frame::Hello`] means the other half just
+    /// enumerated as the USB host, so we are the remote half from now on; a
+    /// [`proto::Frame::Layer`] updates the layer the remote half's underglow
+    /// strip tracks; a [`proto::Frame::Event`] is a (already mirrored, if
+    /// applicable) matrix event forwarded by the remote half, registered
+    /// with the layout as-is. A malformed or CRC-mismatched frame is dropped
+    /// and the link resynchronizes at the next delimiter.
+    #[task(binds = USART1, priority = 2, local = [serial_rx, serial_buf, serial_pos], shared = [is_remote, remote_layer])]
+    fn rx(mut cx: rx::Context) {
         if let Ok(b) = cx.local.serial_rx.read() {
-            cx.local.serial_buf.rotate_left(1);
-            cx.local.serial_buf[3] = b;
+            if *cx.local.serial_pos >= cx.local.serial_buf.len() {
+                // frame too long without a delimiter: resynchronize
+                *cx.local.serial_pos = 0;
+            }
 
-            if cx.local.serial_buf[3] == b'\n' {
-                if let Ok(event) = deserialize(&cx.local.serial_buf[..]) {
-                    defmt::info!("Received message via USART");
-                    register_keyboard_event::spawn(event).unwrap()
+            cx.local.serial_buf[*cx.local.serial_pos] = b;
+            *cx.local.serial_pos += 1;
+
+            if b == 0x00 {
+                match proto::deserialize(&cx.local.serial_buf[..*cx.local.serial_pos]) {
+                    Ok(proto::Frame::Hello) => {
+                        defmt::info!("Other half is host, this half is remote");
+                        cx.shared.is_remote.lock(|r| *r = true);
+                    }
+                    Ok(proto::Frame::Layer(layer)) => {
+                        cx.shared.remote_layer.lock(|l| *l = layer as usize);
+                    }
+                    Ok(proto::Frame::Event(event)) => {
+                        defmt::info!("Received message via USART");
+                        register_keyboard_event::spawn(event).unwrap()
+                    }
+                    Err(()) => (),
                 }
+                *cx.local.serial_pos = 0;
             }
         }
     }
 
-    fn deserialize(bytes: &[u8]) -> Result<Event, ()> {
-        match *bytes {
-            [b'P', i, j, b'\n'] => Ok(Event::Press(i, j)),
-            [b'R', i, j, b'\n'] => Ok(Event::Release(i, j)),
-            _ => Err(()),
-        }
-    }
-
-    fn serialize(e: Event) -> [u8; 4] {
-        match e {
-            Event::Press(i, j) => [b'P', i, j, b'\n'],
-            Event::Release(i, j) => [b'R', i, j, b'\n'],
-        }
+    // USB events: poll the composite device's classes and service any
+    // pending console command on the CDC-ACM serial port.
+    #[task(binds = OTG_FS, priority = 3, local = [console], shared = [usb_dev, usb_class, serial_class, event_log, layer_snapshot, pending_holdtap_timeout])]
+    fn usb_tx(cx: usb_tx::Context) {
+        (
+            cx.shared.usb_dev,
+            cx.shared.usb_class,
+            cx.shared.serial_class,
+            cx.shared.event_log,
+            cx.shared.layer_snapshot,
+            cx.shared.pending_holdtap_timeout,
+        )
+            .lock(
+                |usb_dev,
+                 usb_class,
+                 serial_class,
+                 event_log,
+                 layer_snapshot,
+                 pending_holdtap_timeout| {
+                    usb_poll(usb_dev, usb_class, serial_class);
+                    poll_console(
+                        cx.local.console,
+                        serial_class,
+                        event_log,
+                        layer_snapshot,
+                        usb_dev,
+                        pending_holdtap_timeout,
+                    );
+                },
+            );
     }
 
-    /// Transform key events from other keyboard half by mirroring coordinates
-    #[cfg(feature = "right_half")]
-    fn transform_keypress_coordinates(e: Event) -> Event {
-        // mirror coordinates for events for right half
-        e.transform(|i, j| (i, 11 - j))
+    #[task(binds = OTG_FS_WKUP, priority = 3, shared = [usb_dev, usb_class, serial_class])]
+    fn usb_rx(cx: usb_rx::Context) {
+        (
+            cx.shared.usb_dev,
+            cx.shared.usb_class,
+            cx.shared.serial_class,
+        )
+            .lock(|usb_dev, usb_class, serial_class| {
+                usb_poll(usb_dev, usb_class, serial_class);
+            });
     }
 
-    #[cfg(not(feature = "right_half"))]
-    fn transform_keypress_coordinates(e: Event) -> Event {
-        e
+    fn usb_poll(usb_dev: &mut UsbDevice, keyboard: &mut UsbClass, serial: &mut SerialClass) {
+        if usb_dev.poll(&mut [keyboard, serial]) {
+            keyboard.poll();
+        }
     }
 
-    // USB events
-    #[task(binds = OTG_FS, priority = 3, shared = [usb_dev, usb_class])]
-    fn usb_tx(cx: usb_tx::Context) {
-        (cx.shared.usb_dev, cx.shared.usb_class).lock(|mut usb_dev, mut usb_class| {
-            usb_poll(&mut usb_dev, &mut usb_class);
-        });
-    }
+    /// Reads any bytes waiting on the console's serial port, feeds them to
+    /// the line parser, and executes completed commands.
+    fn poll_console(
+        console: &mut Console,
+        serial: &mut SerialClass,
+        event_log: &mut EventLog,
+        layer_snapshot: &mut LayerSnapshot,
+        usb_dev: &mut UsbDevice,
+        pending_holdtap_timeout: &mut Option<u16>,
+    ) {
+        let mut buf = [0u8; 32];
+        let n = match serial.read(&mut buf) {
+            Ok(n) => n,
+            Err(_) => return,
+        };
 
-    #[task(binds = OTG_FS_WKUP, priority = 3, shared = [usb_dev, usb_class])]
-    fn usb_rx(cx: usb_rx::Context) {
-        (cx.shared.usb_dev, cx.shared.usb_class).lock(|mut usb_dev, mut usb_class| {
-            usb_poll(&mut usb_dev, &mut usb_class);
-        });
+        for &b in &buf[..n] {
+            match console.feed(b) {
+                Some(Command::Layers) => {
+                    write_line(
+                        serial,
+                        format_args!(
+                            "layer={} default={}",
+                            layer_snapshot.current, layer_snapshot.default
+                        ),
+                    );
+                }
+                Some(Command::Log) => {
+                    for event in event_log.iter() {
+                        let (kind, i, j) = match event {
+                            Event::Press(i, j) => ("press", i, j),
+                            Event::Release(i, j) => ("release", i, j),
+                        };
+                        write_line(serial, format_args!("{} {} {}", kind, i, j));
+                    }
+                }
+                Some(Command::Host) => {
+                    let is_host = usb_dev.state() == UsbDeviceState::Configured;
+                    write_line(serial, format_args!("host={}", is_host));
+                }
+                Some(Command::Timeout(ms)) => {
+                    *pending_holdtap_timeout = Some(ms);
+                    write_line(serial, format_args!("timeout={}", ms));
+                }
+                Some(Command::Bootloader) => unsafe { cortex_m::asm::bootload(0x1FFF0000 as _) },
+                None => (),
+            }
+        }
     }
 
-    fn usb_poll(usb_dev: &mut UsbDevice, keyboard: &mut UsbClass) {
-        if usb_dev.poll(&mut [keyboard]) {
-            keyboard.poll();
+    /// Writes a line (plus `\r\n`) to the console, ignoring write errors
+    /// (e.g. no terminal currently attached).
+    fn write_line(serial: &mut SerialClass, args: core::fmt::Arguments) {
+        use core::fmt::Write;
+        let mut line: heapless::String<64> = heapless::String::new();
+        if write!(line, "{}\r\n", args).is_ok() {
+            serial.write(line.as_bytes()).ok();
         }
     }
 }
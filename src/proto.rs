@@ -0,0 +1,143 @@
+//! Framing for the inter-half USART link.
+//!
+//! Each [`Frame`] is serialized as `[tag, i, j, crc8]`, where `crc8` is a
+//! CRC-8 (polynomial 0x07, init 0x00) over the first three bytes. The
+//! payload is then COBS-encoded (Consistent Overhead Byte Stuffing, which
+//! removes all zero bytes from the body) and terminated with a single
+//! `0x00` delimiter. This makes the link self-synchronizing: a coordinate
+//! value of 10 can no longer be mistaken for a frame boundary, and a
+//! corrupted frame is caught by the CRC instead of silently injecting a
+//! phantom press or release.
+//!
+//! Besides forwarding [`Event`]s from the non-host half, the link carries
+//! two housekeeping messages: [`Frame::Hello`], which the host sends once it
+//! enumerates, telling the other half it is the remote half for this boot,
+//! and [`Frame::Layer`], which the host sends whenever the active layout
+//! layer changes, so the remote half's underglow strip can track it too.
+
+use keyberon::layout::Event;
+
+/// Worst-case length of a COBS-encoded, delimited frame for our 4-byte
+/// payload (4 data bytes + 1 COBS overhead byte + 1 delimiter).
+pub const MAX_FRAME_LEN: usize = 6;
+
+/// A message exchanged over the inter-half USART link.
+pub enum Frame {
+    /// A debounced matrix event, forwarded by the remote half to the host.
+    Event(Event),
+    /// Sent by the host once enumerated, telling the other half it is the
+    /// remote half for this boot.
+    Hello,
+    /// Sent by the host whenever the active layout layer changes, so the
+    /// remote half's underglow strip can mirror it.
+    Layer(u8),
+}
+
+/// Computes a CRC-8 (polynomial 0x07, init 0x00) over `bytes`.
+fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &b in bytes {
+        crc ^= b;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// COBS-encodes `input` into `output`, returning the number of bytes written
+/// (not including the trailing `0x00` delimiter).
+fn cobs_encode(input: &[u8], output: &mut [u8]) -> usize {
+    let mut out_i = 1;
+    let mut code_i = 0;
+    let mut code = 1u8;
+
+    for &b in input {
+        if b == 0 {
+            output[code_i] = code;
+            code = 1;
+            code_i = out_i;
+            out_i += 1;
+        } else {
+            output[out_i] = b;
+            out_i += 1;
+            code += 1;
+            if code == 0xFF {
+                output[code_i] = code;
+                code = 1;
+                code_i = out_i;
+                out_i += 1;
+            }
+        }
+    }
+    output[code_i] = code;
+    out_i
+}
+
+/// COBS-decodes `input` (the frame without its trailing delimiter) into
+/// `output`, returning the number of bytes written.
+fn cobs_decode(input: &[u8], output: &mut [u8]) -> Result<usize, ()> {
+    let mut in_i = 0;
+    let mut out_i = 0;
+
+    while in_i < input.len() {
+        let code = input[in_i] as usize;
+        if code == 0 || in_i + code > input.len() + 1 {
+            return Err(());
+        }
+        in_i += 1;
+        for _ in 1..code {
+            let b = *input.get(in_i).ok_or(())?;
+            *output.get_mut(out_i).ok_or(())? = b;
+            out_i += 1;
+            in_i += 1;
+        }
+        if code != 0xFF && in_i < input.len() {
+            *output.get_mut(out_i).ok_or(())? = 0;
+            out_i += 1;
+        }
+    }
+    Ok(out_i)
+}
+
+/// Serializes a [`Frame`] into `output` as a COBS-framed, CRC-checked USART
+/// frame (including the trailing `0x00` delimiter), returning its length.
+pub fn serialize(f: Frame, output: &mut [u8; MAX_FRAME_LEN]) -> usize {
+    let (tag, i, j) = match f {
+        Frame::Event(Event::Press(i, j)) => (b'P', i, j),
+        Frame::Event(Event::Release(i, j)) => (b'R', i, j),
+        Frame::Hello => (b'H', 0, 0),
+        Frame::Layer(layer) => (b'L', layer, 0),
+    };
+    let payload = [tag, i, j, crc8(&[tag, i, j])];
+    let len = cobs_encode(&payload, output);
+    output[len] = 0x00;
+    len + 1
+}
+
+/// Deserializes a COBS-framed buffer (including the trailing `0x00`
+/// delimiter) back into a [`Frame`], rejecting it on a CRC mismatch.
+pub fn deserialize(frame: &[u8]) -> Result<Frame, ()> {
+    if frame.last() != Some(&0x00) {
+        return Err(());
+    }
+    let mut payload = [0u8; 4];
+    let len = cobs_decode(&frame[..frame.len() - 1], &mut payload)?;
+    if len != payload.len() {
+        return Err(());
+    }
+    if crc8(&payload[..3]) != payload[3] {
+        return Err(());
+    }
+    match payload {
+        [b'P', i, j, _] => Ok(Frame::Event(Event::Press(i, j))),
+        [b'R', i, j, _] => Ok(Frame::Event(Event::Release(i, j))),
+        [b'H', _, _, _] => Ok(Frame::Hello),
+        [b'L', layer, _, _] => Ok(Frame::Layer(layer)),
+        _ => Err(()),
+    }
+}